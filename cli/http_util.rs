@@ -0,0 +1,267 @@
+// Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+use bytes::Bytes;
+use deno_core::ErrBox;
+use futures::Stream;
+use futures::StreamExt;
+use reqwest::Client;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+use tokio::io::AsyncRead;
+
+/// A proxy the built client should route all requests through, with
+/// optional basic-auth credentials.
+#[derive(Clone)]
+pub struct Proxy {
+  pub url: String,
+  pub basic_auth: Option<(String, String)>,
+}
+
+#[derive(Clone, Default)]
+pub struct CreateHttpClientOptions {
+  pub ca_file: Option<String>,
+  pub proxy: Option<Proxy>,
+  /// PEM-encoded client certificate chain and private key, for mutual TLS.
+  /// Both must be present together.
+  pub client_cert_chain_and_key: Option<(String, String)>,
+  /// Redirect policy the built client should follow. Kept alongside the
+  /// other options (rather than applied separately) so a client can be
+  /// rebuilt with a different policy without losing its CA/proxy/identity
+  /// configuration, e.g. for `fetch`'s "manual"/"error" redirect modes.
+  pub redirect: Redirect,
+}
+
+/// Mirrors the `fetch` spec's `RequestRedirect` enum.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Redirect {
+  Follow,
+  Manual,
+  Error,
+}
+
+impl Default for Redirect {
+  fn default() -> Self {
+    Redirect::Follow
+  }
+}
+
+impl Redirect {
+  fn to_policy(self) -> reqwest::redirect::Policy {
+    match self {
+      Redirect::Follow => reqwest::redirect::Policy::default(),
+      Redirect::Manual | Redirect::Error => reqwest::redirect::Policy::none(),
+    }
+  }
+}
+
+/// Creates an instance of `reqwest::Client`, optionally adding a custom CA
+/// certificate to the trusted roots (alongside the built-in ones) for TLS
+/// verification, routing through a proxy, and/or
+/// presenting a client certificate for mutual TLS.
+pub fn create_http_client(
+  options: CreateHttpClientOptions,
+) -> Result<Client, ErrBox> {
+  let mut builder = Client::builder()
+    .use_rustls_tls()
+    .redirect(options.redirect.to_policy());
+
+  if let Some(ca_file) = options.ca_file {
+    let mut buf = Vec::new();
+    File::open(ca_file)?.read_to_end(&mut buf)?;
+    let cert = reqwest::Certificate::from_pem(&buf)?;
+    builder = builder.add_root_certificate(cert);
+  }
+
+  if let Some(proxy) = options.proxy {
+    let mut reqwest_proxy = reqwest::Proxy::all(&proxy.url)?;
+    if let Some((username, password)) = proxy.basic_auth {
+      reqwest_proxy = reqwest_proxy.basic_auth(&username, &password);
+    }
+    builder = builder.proxy(reqwest_proxy);
+  }
+
+  if let Some((cert_chain, private_key)) = options.client_cert_chain_and_key {
+    // `reqwest::Identity::from_pem` expects the certificate chain and key
+    // concatenated into a single PEM document; a newline is inserted
+    // between them in case `cert_chain` doesn't already end in one, or the
+    // two PEM blocks would run together on one line and fail to parse.
+    let mut pem = cert_chain.into_bytes();
+    pem.push(b'\n');
+    pem.extend_from_slice(private_key.as_bytes());
+    let identity = reqwest::Identity::from_pem(&pem)?;
+    builder = builder.identity(identity);
+  }
+
+  builder.build().map_err(ErrBox::from)
+}
+
+/// A cancellation signal shared across any number of in-flight operations,
+/// e.g. several `fetch()` calls -- or a fetch and its in-progress response
+/// body read -- made against the same `AbortController`. Aborting wakes
+/// every waker registered via `poll_aborted` (so a consumer parked in
+/// `Poll::Pending` is woken immediately rather than on its next unrelated
+/// wakeup), and any call made after abort sees it immediately via
+/// `is_aborted()`.
+pub struct CancelHandle {
+  aborted: Cell<bool>,
+  wakers: RefCell<Vec<Waker>>,
+}
+
+/// Returned by `CancelHandle::race` when the handle was aborted before the
+/// raced future completed.
+pub struct Aborted;
+
+impl CancelHandle {
+  pub fn new() -> Self {
+    Self {
+      aborted: Cell::new(false),
+      wakers: RefCell::new(Vec::new()),
+    }
+  }
+
+  pub fn abort(&self) {
+    self.aborted.set(true);
+    for waker in self.wakers.borrow_mut().drain(..) {
+      waker.wake();
+    }
+  }
+
+  pub fn is_aborted(&self) -> bool {
+    self.aborted.get()
+  }
+
+  /// Polls whether this handle has been aborted, registering `cx`'s waker
+  /// to be woken by the next `abort()` call if not. Safe to call from a
+  /// manual `Future`/`AsyncRead` poll impl -- unlike `race`, it doesn't
+  /// need to own or `.await` the future being raced against cancellation.
+  pub fn poll_aborted(&self, cx: &mut Context) -> Poll<()> {
+    if self.is_aborted() {
+      return Poll::Ready(());
+    }
+    let mut wakers = self.wakers.borrow_mut();
+    if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+      wakers.push(cx.waker().clone());
+    }
+    Poll::Pending
+  }
+
+  /// Races `fut` against abort. Unlike `futures::future::Abortable`, this
+  /// can be called any number of times concurrently on the same handle.
+  pub async fn race<F: std::future::Future>(
+    &self,
+    fut: F,
+  ) -> Result<F::Output, Aborted> {
+    futures::pin_mut!(fut);
+    futures::future::poll_fn(|cx| {
+      if let Poll::Ready(res) = std::future::Future::poll(fut.as_mut(), cx) {
+        return Poll::Ready(Ok(res));
+      }
+      self.poll_aborted(cx).map(|()| Err(Aborted))
+    })
+    .await
+  }
+}
+
+impl Default for CancelHandle {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>>>>;
+
+/// Wraps a `reqwest::Response` body so it can be consumed as an `AsyncRead`,
+/// which backs the `bodyRid` streaming resource handed back to JS.
+pub struct HttpBody {
+  stream: ByteStream,
+  chunk: Option<Bytes>,
+  pos: usize,
+  cancel_handle: Option<Rc<CancelHandle>>,
+}
+
+impl HttpBody {
+  /// `cancel_handle` is the same handle backing the `op_fetch` call that
+  /// produced `response`, so a body read in progress observes cancellation
+  /// just like the initial request does.
+  pub fn new(
+    response: reqwest::Response,
+    cancel_handle: Option<Rc<CancelHandle>>,
+  ) -> Self {
+    Self {
+      stream: response.bytes_stream().boxed_local(),
+      chunk: None,
+      pos: 0,
+      cancel_handle,
+    }
+  }
+}
+
+impl From<reqwest::Response> for HttpBody {
+  fn from(response: reqwest::Response) -> Self {
+    Self::new(response, None)
+  }
+}
+
+impl AsyncRead for HttpBody {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context,
+    buf: &mut [u8],
+  ) -> Poll<std::io::Result<usize>> {
+    let inner = self.get_mut();
+    if let Some(handle) = &inner.cancel_handle {
+      if handle.is_aborted() {
+        return Poll::Ready(Err(std::io::Error::new(
+          std::io::ErrorKind::Other,
+          "The operation was aborted",
+        )));
+      }
+    }
+    loop {
+      if let Some(chunk) = inner.chunk.take() {
+        let remaining = &chunk[inner.pos..];
+        let n = std::cmp::min(remaining.len(), buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        if inner.pos + n < chunk.len() {
+          inner.pos += n;
+          inner.chunk = Some(chunk);
+        } else {
+          inner.pos = 0;
+        }
+        return Poll::Ready(Ok(n));
+      }
+
+      match inner.stream.as_mut().poll_next(cx) {
+        Poll::Ready(Some(Ok(chunk))) => inner.chunk = Some(chunk),
+        Poll::Ready(Some(Err(e))) => {
+          return Poll::Ready(Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            e,
+          )))
+        }
+        Poll::Ready(None) => return Poll::Ready(Ok(0)),
+        Poll::Pending => {
+          // Register with the cancel handle too, so a read parked here --
+          // the common case for a slow/long-running body -- is woken
+          // immediately by `abort()` rather than only on the stream's own
+          // next natural wakeup.
+          if let Some(handle) = &inner.cancel_handle {
+            if let Poll::Ready(()) = handle.poll_aborted(cx) {
+              return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "The operation was aborted",
+              )));
+            }
+          }
+          return Poll::Pending;
+        }
+      }
+    }
+  }
+}
@@ -1,20 +1,33 @@
 // Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
 use super::dispatch_json::{Deserialize, JsonOp, Value};
 use super::io::{StreamResource, StreamResourceHolder};
-use crate::http_util::{create_http_client, HttpBody};
+use crate::http_util::{
+  create_http_client, CancelHandle, CreateHttpClientOptions as HttpClientCreateOptions,
+  HttpBody, Proxy as HttpProxy, Redirect,
+};
 use crate::state::State;
 use deno_core::CoreIsolate;
 use deno_core::CoreIsolateState;
 use deno_core::ErrBox;
+use deno_core::ResourceTable;
 use deno_core::ZeroCopyBuf;
 use futures::future::FutureExt;
+use futures::Stream;
+use futures::StreamExt;
 use http::header::HeaderName;
 use http::header::HeaderValue;
 use http::Method;
 use reqwest::Client;
+use std::cell::RefCell;
 use std::convert::From;
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::rc::Rc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
 
 pub fn init(i: &mut CoreIsolate, s: &Rc<State>) {
   i.register_op("op_fetch", s.stateful_json_op2(op_fetch));
@@ -22,6 +35,24 @@ pub fn init(i: &mut CoreIsolate, s: &Rc<State>) {
     "op_create_http_client",
     s.stateful_json_op2(op_create_http_client),
   );
+  i.register_op(
+    "op_create_cancel_handle",
+    s.stateful_json_op2(op_create_cancel_handle),
+  );
+  i.register_op("op_fetch_abort", s.stateful_json_op2(op_fetch_abort));
+}
+
+/// Constructs the `AbortError` reported to JS when a fetch is aborted via an
+/// explicit `abort_rid` signal.
+fn abort_error() -> ErrBox {
+  ErrBox::new("AbortError", "The operation was aborted")
+}
+
+/// Constructs the `TimeoutError` reported to JS when a fetch's `timeout_ms`
+/// deadline elapses -- distinct from `abort_error`, since a timeout isn't a
+/// caller-initiated abort.
+fn timeout_error() -> ErrBox {
+  ErrBox::new("TimeoutError", "The operation timed out")
 }
 
 #[derive(Deserialize)]
@@ -31,6 +62,125 @@ struct FetchArgs {
   url: String,
   headers: Vec<(String, String)>,
   client_rid: Option<u32>,
+  /// One of "follow" (default), "manual" or "error", mirroring the `fetch`
+  /// spec's `RequestRedirect` enum.
+  redirect: Option<String>,
+  /// Rid of a `FetchCancelHandle` created via `op_create_cancel_handle`,
+  /// backing `AbortController`/`AbortSignal` on the JS side.
+  abort_rid: Option<u32>,
+  /// Milliseconds until the request is aborted, for a simple per-request
+  /// timeout independent of (or combined with) `abort_rid`.
+  timeout_ms: Option<u64>,
+  /// Rid of a `StreamResource` to stream the request body from, instead of
+  /// passing the whole body as a single zero-copy buffer. Mutually
+  /// exclusive with the buffer passed via `data`.
+  body_rid: Option<u32>,
+}
+
+/// Parses the `redirect` arg into a `Redirect`, mirroring the `fetch`
+/// spec's `RequestRedirect` enum. Pulled out of `op_fetch` so the mapping
+/// (and its error case) can be unit tested without constructing an op's
+/// full argument set.
+fn parse_redirect_mode(mode: &str) -> Result<Redirect, ErrBox> {
+  match mode {
+    "follow" => Ok(Redirect::Follow),
+    "manual" => Ok(Redirect::Manual),
+    "error" => Ok(Redirect::Error),
+    _ => Err(ErrBox::type_error(format!(
+      "invalid redirect mode '{}'",
+      mode
+    ))),
+  }
+}
+
+/// `bodyRid` and a zero-copy body buffer are mutually exclusive ways of
+/// supplying a request body; this rejects a call that supplies both.
+fn check_body_rid_exclusive(
+  body_rid: Option<u32>,
+  data_len: usize,
+) -> Result<(), ErrBox> {
+  if body_rid.is_some() && data_len > 0 {
+    return Err(ErrBox::type_error(
+      "bodyRid and a zero-copy body buffer are mutually exclusive".to_string(),
+    ));
+  }
+  Ok(())
+}
+
+/// Adapts a `StreamResource` upload source into a `futures::Stream` of
+/// chunks for `reqwest::Body::wrap_stream`. The resource table is only
+/// borrowed for the duration of a single `poll_read` call, never across an
+/// await point, so a suspended upload doesn't block unrelated ops from
+/// borrowing the same table.
+struct BodyRidStream {
+  resource_table: Rc<RefCell<ResourceTable>>,
+  rid: u32,
+  buf: Vec<u8>,
+}
+
+impl Stream for BodyRidStream {
+  type Item = Result<Vec<u8>, std::io::Error>;
+
+  fn poll_next(
+    self: Pin<&mut Self>,
+    cx: &mut Context,
+  ) -> Poll<Option<Self::Item>> {
+    let this = self.get_mut();
+    let mut table = this.resource_table.borrow_mut();
+    let holder = match table.get_mut::<StreamResourceHolder>(this.rid) {
+      Some(holder) => holder,
+      None => {
+        return Poll::Ready(Some(Err(std::io::Error::new(
+          std::io::ErrorKind::NotFound,
+          "invalid bodyRid",
+        ))))
+      }
+    };
+    match Pin::new(holder).poll_read(cx, &mut this.buf) {
+      Poll::Ready(Ok(0)) => Poll::Ready(None),
+      Poll::Ready(Ok(n)) => Poll::Ready(Some(Ok(this.buf[..n].to_vec()))),
+      Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+      Poll::Pending => Poll::Pending,
+    }
+  }
+}
+
+/// The `Send + Sync` half of the `BodyRidStream` bridge: just a channel
+/// receiver, handed to `reqwest::Body::wrap_stream` so hyper can drive the
+/// request body from its own connection task.
+struct BodyRidReceiverStream(mpsc::Receiver<Result<Vec<u8>, std::io::Error>>);
+
+impl Stream for BodyRidReceiverStream {
+  type Item = Result<Vec<u8>, std::io::Error>;
+
+  fn poll_next(
+    self: Pin<&mut Self>,
+    cx: &mut Context,
+  ) -> Poll<Option<Self::Item>> {
+    self.get_mut().0.poll_recv(cx)
+  }
+}
+
+/// Pumps `body_stream` into a bounded channel and returns the receiving
+/// half. `BodyRidStream` holds `Rc<RefCell<ResourceTable>>` and so is
+/// `!Send`, but `reqwest::Body::wrap_stream` requires `Send + Sync` (hyper
+/// drives the request body on its own task) -- it can't be handed the
+/// resource-table reader directly. Instead `body_stream` is driven to
+/// completion on a separate task spawned locally on this thread, one chunk
+/// at a time, and each chunk is
+/// forwarded through the channel; only the receiver, which owns no
+/// `!Send` state, crosses into the body hyper drives elsewhere.
+fn spawn_body_rid_bridge(body_stream: BodyRidStream) -> BodyRidReceiverStream {
+  let (tx, rx) = mpsc::channel::<Result<Vec<u8>, std::io::Error>>(1);
+  tokio::task::spawn_local(async move {
+    let mut body_stream = body_stream;
+    while let Some(chunk) = body_stream.next().await {
+      if tx.send(chunk).await.is_err() {
+        break;
+      }
+    }
+  });
+  BodyRidReceiverStream(rx)
 }
 
 pub fn op_fetch(
@@ -43,16 +193,58 @@ pub fn op_fetch(
   let url = args.url;
   let resource_table_ = isolate_state.resource_table.borrow();
 
+  let redirect_mode = args.redirect.as_deref().unwrap_or("follow").to_string();
+  let redirect = parse_redirect_mode(&redirect_mode)?;
+
   let mut client_ref_mut;
-  let client = if let Some(rid) = args.client_rid {
+  let redirect_client;
+  // `reqwest::Client` bakes its redirect policy in at construction time, so
+  // "manual"/"error" can't just override the policy on the client the
+  // caller asked for -- a new client has to be built. It's rebuilt from
+  // that same client's options (proxy, CA, mTLS identity) rather than from
+  // scratch, so only the redirect policy actually changes.
+  let client = match (args.client_rid, redirect) {
+    (Some(rid), Redirect::Follow) => {
+      let r = resource_table_
+        .get::<HttpClientResource>(rid)
+        .ok_or_else(ErrBox::bad_resource_id)?;
+      &r.client
+    }
+    (Some(rid), _) => {
+      let r = resource_table_
+        .get::<HttpClientResource>(rid)
+        .ok_or_else(ErrBox::bad_resource_id)?;
+      let mut options = r.options.clone();
+      options.redirect = redirect;
+      redirect_client = create_http_client(options)?;
+      &redirect_client
+    }
+    (None, Redirect::Follow) => {
+      client_ref_mut = state.http_client.borrow_mut();
+      &mut *client_ref_mut
+    }
+    (None, _) => {
+      // The default client (no client_rid) never carries proxy/CA/mTLS
+      // config of its own -- those are only reachable via
+      // `Deno.createHttpClient` -- so matching its TLS stack is all there
+      // is to preserve here.
+      redirect_client = create_http_client(HttpClientCreateOptions {
+        redirect,
+        ..Default::default()
+      })?;
+      &redirect_client
+    }
+  };
+
+  let cancel_handle = if let Some(rid) = args.abort_rid {
     let r = resource_table_
-      .get::<HttpClientResource>(rid)
+      .get::<FetchCancelHandle>(rid)
       .ok_or_else(ErrBox::bad_resource_id)?;
-    &r.client
+    Some(r.0.clone())
   } else {
-    client_ref_mut = state.http_client.borrow_mut();
-    &mut *client_ref_mut
+    None
   };
+  let timeout_ms = args.timeout_ms;
 
   let method = match args.method {
     Some(method_str) => Method::from_bytes(method_str.as_bytes())?,
@@ -72,12 +264,30 @@ pub fn op_fetch(
 
   state.check_net_url(&url_)?;
 
+  // Captured before `url_` moves into `client.request` below, so it's the
+  // normalized URL reqwest will actually request -- compared against
+  // `res.url()` later to compute `redirected`.
+  let request_url = url_.to_string();
   let mut request = client.request(method, url_);
 
-  match data.len() {
-    0 => {}
-    1 => request = request.body(Vec::from(&*data[0])),
-    _ => panic!("Invalid number of arguments"),
+  check_body_rid_exclusive(args.body_rid, data.len())?;
+  match (args.body_rid, data.len()) {
+    (Some(body_rid), _) => {
+      // Stream the body out of the resource table in chunks instead of
+      // buffering it all in memory up front, mirroring the `bodyRid`
+      // streaming download path below.
+      let body_stream = BodyRidStream {
+        resource_table: isolate_state.resource_table.clone(),
+        rid: body_rid,
+        buf: vec![0u8; 64 * 1024],
+      };
+      request = request.body(reqwest::Body::wrap_stream(
+        spawn_body_rid_bridge(body_stream),
+      ));
+    }
+    (None, 0) => {}
+    (None, 1) => request = request.body(Vec::from(&*data[0])),
+    (None, _) => panic!("Invalid number of arguments"),
   }
 
   for (key, value) in args.headers {
@@ -89,15 +299,47 @@ pub fn op_fetch(
 
   let resource_table = isolate_state.resource_table.clone();
   let future = async move {
-    let res = request.send().await?;
+    let send_fut = request.send();
+    let res = match (&cancel_handle, timeout_ms) {
+      (Some(handle), Some(ms)) => {
+        match time::timeout(Duration::from_millis(ms), handle.race(send_fut)).await {
+          Ok(Ok(Ok(res))) => res,
+          Ok(Ok(Err(e))) => return Err(ErrBox::from(e)),
+          Ok(Err(_aborted)) => return Err(abort_error()),
+          Err(_elapsed) => return Err(timeout_error()),
+        }
+      }
+      (Some(handle), None) => match handle.race(send_fut).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => return Err(ErrBox::from(e)),
+        Err(_aborted) => return Err(abort_error()),
+      },
+      (None, Some(ms)) => match time::timeout(Duration::from_millis(ms), send_fut).await
+      {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => return Err(ErrBox::from(e)),
+        Err(_elapsed) => return Err(timeout_error()),
+      },
+      (None, None) => send_fut.await?,
+    };
     debug!("Fetch response {}", url);
     let status = res.status();
+
+    if redirect_mode == "error" && status.is_redirection() {
+      return Err(ErrBox::type_error(format!(
+        "redirect encountered in 'error' redirect mode for {}",
+        url
+      )));
+    }
+
+    let final_url = res.url().to_string();
+    let redirected = final_url != request_url;
     let mut res_headers = Vec::new();
     for (key, val) in res.headers().iter() {
       res_headers.push((key.to_string(), val.to_str().unwrap().to_owned()));
     }
 
-    let body = HttpBody::from(res);
+    let body = HttpBody::new(res, cancel_handle);
     let mut resource_table = resource_table.borrow_mut();
     let rid = resource_table.add(
       "httpBody",
@@ -110,7 +352,9 @@ pub fn op_fetch(
       "bodyRid": rid,
       "status": status.as_u16(),
       "statusText": status.canonical_reason().unwrap_or(""),
-      "headers": res_headers
+      "headers": res_headers,
+      "url": final_url,
+      "redirected": redirected
     });
 
     Ok(json_res)
@@ -121,19 +365,133 @@ pub fn op_fetch(
 
 struct HttpClientResource {
   client: Client,
+  /// Options the client was built from, kept around so a redirect-mode
+  /// override (see `op_fetch`) can rebuild it without losing its proxy, CA
+  /// or mTLS identity configuration.
+  options: HttpClientCreateOptions,
 }
 
 impl HttpClientResource {
-  fn new(client: Client) -> Self {
-    Self { client }
+  fn new(client: Client, options: HttpClientCreateOptions) -> Self {
+    Self { client, options }
   }
 }
 
+/// Backs a JS-visible handle (e.g. `AbortController`) that can cancel any
+/// number of pending `op_fetch` calls sharing the same rid via
+/// `op_fetch_abort`. Wraps `Rc<CancelHandle>` so each fetch can clone its
+/// own reference and race its own future against the shared signal, rather
+/// than consuming a single-use registration.
+struct FetchCancelHandle(Rc<CancelHandle>);
+
+fn op_create_cancel_handle(
+  isolate_state: &mut CoreIsolateState,
+  _state: &Rc<State>,
+  _args: Value,
+  _zero_copy: &mut [ZeroCopyBuf],
+) -> Result<JsonOp, ErrBox> {
+  let mut resource_table = isolate_state.resource_table.borrow_mut();
+  let rid = resource_table.add(
+    "fetchCancelHandle",
+    Box::new(FetchCancelHandle(Rc::new(CancelHandle::new()))),
+  );
+  Ok(JsonOp::Sync(json!(rid)))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FetchAbortArgs {
+  rid: u32,
+}
+
+fn op_fetch_abort(
+  isolate_state: &mut CoreIsolateState,
+  _state: &Rc<State>,
+  args: Value,
+  _zero_copy: &mut [ZeroCopyBuf],
+) -> Result<JsonOp, ErrBox> {
+  let args: FetchAbortArgs = serde_json::from_value(args)?;
+  let resource_table = isolate_state.resource_table.borrow();
+  let r = resource_table
+    .get::<FetchCancelHandle>(args.rid)
+    .ok_or_else(ErrBox::bad_resource_id)?;
+  r.0.abort();
+  Ok(JsonOp::Sync(json!({})))
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+#[serde(default)]
+struct ProxyConfig {
+  url: String,
+  username: Option<String>,
+  password: Option<String>,
+}
+
 #[derive(Deserialize, Default, Debug)]
 #[serde(rename_all = "camelCase")]
 #[serde(default)]
 struct CreateHttpClientOptions {
   ca_file: Option<String>,
+  proxy: Option<ProxyConfig>,
+  /// Inline PEM client certificate chain, for mutual TLS. Mutually
+  /// exclusive with `cert_chain_file`.
+  cert_chain: Option<String>,
+  /// Path to a PEM client certificate chain, for mutual TLS.
+  cert_chain_file: Option<String>,
+  /// Inline PEM private key matching `cert_chain`/`cert_chain_file`.
+  private_key: Option<String>,
+  /// Path to a PEM private key matching `cert_chain`/`cert_chain_file`.
+  private_key_file: Option<String>,
+}
+
+/// Resolves the (possibly file-backed) client certificate chain and private
+/// key into PEM strings, checking read permission for any path given.
+/// Returns `None` if neither was configured.
+fn resolve_client_cert_and_key(
+  state: &Rc<State>,
+  args: &CreateHttpClientOptions,
+) -> Result<Option<(String, String)>, ErrBox> {
+  fn resolve(
+    state: &Rc<State>,
+    inline: &Option<String>,
+    file: &Option<String>,
+  ) -> Result<Option<String>, ErrBox> {
+    match (inline, file) {
+      (Some(_), Some(_)) => Err(ErrBox::type_error(
+        "only one of an inline PEM value and a PEM file path may be specified"
+          .to_string(),
+      )),
+      (Some(inline), None) => Ok(Some(inline.clone())),
+      (None, Some(file)) => {
+        state.check_read(&PathBuf::from(file))?;
+        Ok(Some(std::fs::read_to_string(file)?))
+      }
+      (None, None) => Ok(None),
+    }
+  }
+
+  let cert_chain = resolve(state, &args.cert_chain, &args.cert_chain_file)?;
+  let private_key = resolve(state, &args.private_key, &args.private_key_file)?;
+
+  pair_cert_and_key(cert_chain, private_key)
+}
+
+/// Requires that a client certificate chain and private key are either both
+/// given or both absent. Pulled out of `resolve_client_cert_and_key` so this
+/// check can be unit tested without touching the filesystem or `State`.
+fn pair_cert_and_key(
+  cert_chain: Option<String>,
+  private_key: Option<String>,
+) -> Result<Option<(String, String)>, ErrBox> {
+  match (cert_chain, private_key) {
+    (Some(cert_chain), Some(private_key)) => Ok(Some((cert_chain, private_key))),
+    (None, None) => Ok(None),
+    _ => Err(ErrBox::type_error(
+      "both a client certificate chain and a private key are required for mutual TLS"
+        .to_string(),
+    )),
+  }
 }
 
 fn op_create_http_client(
@@ -149,9 +507,98 @@ fn op_create_http_client(
     state.check_read(&PathBuf::from(ca_file))?;
   }
 
-  let client = create_http_client(args.ca_file.as_deref()).unwrap();
+  // Proxying is gated behind the same net permission a direct request to
+  // the proxy host would require.
+  if let Some(proxy) = &args.proxy {
+    let proxy_url = url::Url::parse(&proxy.url)?;
+    state.check_net_url(&proxy_url)?;
+  }
+
+  let client_cert_chain_and_key = resolve_client_cert_and_key(state, &args)?;
+
+  let proxy = args
+    .proxy
+    .map(|p| -> Result<HttpProxy, ErrBox> {
+      let basic_auth = match (p.username, p.password) {
+        // A username with no password is valid basic-auth (empty
+        // password); a password with no username is not.
+        (Some(username), password) => Some((username, password.unwrap_or_default())),
+        (None, Some(_)) => {
+          return Err(ErrBox::type_error(
+            "proxy password given without a username".to_string(),
+          ))
+        }
+        (None, None) => None,
+      };
+      Ok(HttpProxy {
+        url: p.url,
+        basic_auth,
+      })
+    })
+    .transpose()?;
 
-  let rid =
-    resource_table.add("httpClient", Box::new(HttpClientResource::new(client)));
+  let client_options = HttpClientCreateOptions {
+    ca_file: args.ca_file,
+    proxy,
+    client_cert_chain_and_key,
+    redirect: Redirect::Follow,
+  };
+  let client = create_http_client(client_options.clone()).unwrap();
+
+  let rid = resource_table.add(
+    "httpClient",
+    Box::new(HttpClientResource::new(client, client_options)),
+  );
   Ok(JsonOp::Sync(json!(rid)))
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_redirect_mode_valid() {
+    assert!(matches!(parse_redirect_mode("follow"), Ok(Redirect::Follow)));
+    assert!(matches!(parse_redirect_mode("manual"), Ok(Redirect::Manual)));
+    assert!(matches!(parse_redirect_mode("error"), Ok(Redirect::Error)));
+  }
+
+  #[test]
+  fn parse_redirect_mode_invalid() {
+    assert!(parse_redirect_mode("bogus").is_err());
+  }
+
+  #[test]
+  fn body_rid_and_buffer_are_exclusive() {
+    assert!(check_body_rid_exclusive(Some(1), 1).is_err());
+  }
+
+  #[test]
+  fn body_rid_alone_is_allowed() {
+    assert!(check_body_rid_exclusive(Some(1), 0).is_ok());
+  }
+
+  #[test]
+  fn buffer_alone_is_allowed() {
+    assert!(check_body_rid_exclusive(None, 1).is_ok());
+  }
+
+  #[test]
+  fn cert_chain_and_key_must_both_be_present() {
+    assert!(pair_cert_and_key(Some("chain".to_string()), None).is_err());
+    assert!(pair_cert_and_key(None, Some("key".to_string())).is_err());
+  }
+
+  #[test]
+  fn cert_chain_and_key_together_are_ok() {
+    let result =
+      pair_cert_and_key(Some("chain".to_string()), Some("key".to_string()))
+        .unwrap();
+    assert_eq!(result, Some(("chain".to_string(), "key".to_string())));
+  }
+
+  #[test]
+  fn neither_cert_chain_nor_key_is_ok() {
+    assert_eq!(pair_cert_and_key(None, None).unwrap(), None);
+  }
+}